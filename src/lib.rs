@@ -1,4 +1,7 @@
 use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 
 mod ops;
 
@@ -15,10 +18,14 @@ macro_rules! impl_node_for {
         }
 
         impl RevdepForwarder for $ty {
-            fn forward_add_revdep(&self, _revdep: Ref<UpdateableNode>) {
+            fn forward_add_revdep(&self, _revdep: Ref<UpdateableNode>) -> Result<(), GraphError> {
+                Ok(())
             }
             fn forward_remove_revdep(&self, _revdep: Ref<UpdateableNode>) {
             }
+            fn height(&self) -> usize {
+                0
+            }
         }
     );
 
@@ -31,6 +38,16 @@ macro_rules! impl_node_for {
 pub type Ref<T> = std::rc::Rc<T>;
 pub type WeakRef<T> = std::rc::Weak<T>;
 
+/// A process-wide, monotonically increasing identifier assigned to a node
+/// at construction, used instead of pointer comparison for stable identity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(u64);
+
+fn next_node_id() -> NodeId {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    NodeId(COUNTER.fetch_add(1, AtomicOrdering::Relaxed))
+}
+
 pub trait Node {
     type Output;
 
@@ -51,36 +68,97 @@ impl_node_for!(bool,
                f32,
                f64);
 
+/// An error indicating that an operation on the dependency graph was rejected.
+#[derive(Debug)]
+pub enum GraphError {
+    /// Installing the reverse dependency would close a cycle.
+    Cycle,
+}
+
+impl ::std::fmt::Display for GraphError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            GraphError::Cycle => write!(f, "would introduce a dependency cycle"),
+        }
+    }
+}
+
+impl ::std::error::Error for GraphError {}
+
+/// A node that can be told to recompute itself in response to an upstream change.
 pub trait UpdateableNode {
     fn update(&self);
+
+    /// `1 + max(height of this node's dependencies)`; used to update nodes in
+    /// dependency order during propagation.
+    fn height(&self) -> usize;
+
+    /// The reverse dependencies currently registered on this node.
+    fn revdeps(&self) -> Vec<Ref<UpdateableNode>>;
+
+    /// This node's stable identity.
+    fn id(&self) -> NodeId;
+}
+
+/// Returns whether `target` is reachable by walking revdep edges from `start`.
+fn is_reachable(start: &Ref<UpdateableNode>, target: NodeId) -> bool {
+    let mut visited = HashSet::new();
+    let mut stack = vec![start.clone()];
+
+    while let Some(node) = stack.pop() {
+        if node.id() == target {
+            return true;
+        }
+
+        if !visited.insert(node.id()) {
+            continue;
+        }
+
+        stack.extend(node.revdeps());
+    }
+
+    false
 }
 
 pub trait UpdatingNode: Node {
-    fn add_revdep(&self, revdep: Ref<UpdateableNode>);
+    fn add_revdep(&self, revdep: Ref<UpdateableNode>) -> Result<(), GraphError>;
     fn remove_revdep(&self, revdep: Ref<UpdateableNode>);
+
+    /// Defaults to `0`; `CachedNode`/`LazyCachedNode` override it.
+    fn height(&self) -> usize {
+        0
+    }
 }
 
 pub trait RevdepForwarder {
-    fn forward_add_revdep(&self, revdep: Ref<UpdateableNode>);
+    fn forward_add_revdep(&self, revdep: Ref<UpdateableNode>) -> Result<(), GraphError>;
     fn forward_remove_revdep(&self, revdep: Ref<UpdateableNode>);
+
+    /// The largest height among the updating nodes this forwards to (`0` if
+    /// none); a node wrapping this one takes one more than this as its height.
+    fn height(&self) -> usize;
 }
 
 impl<T> RevdepForwarder for T
     where T: UpdatingNode
 {
-    fn forward_add_revdep(&self, revdep: Ref<UpdateableNode>) {
-        self.add_revdep(revdep);
+    fn forward_add_revdep(&self, revdep: Ref<UpdateableNode>) -> Result<(), GraphError> {
+        self.add_revdep(revdep)
     }
 
     fn forward_remove_revdep(&self, revdep: Ref<UpdateableNode>) {
         self.remove_revdep(revdep);
     }
+
+    fn height(&self) -> usize {
+        UpdatingNode::height(self)
+    }
 }
 
 pub trait CachableNode: Node + RevdepForwarder {}
 impl<T: Node + RevdepForwarder> CachableNode for T where T::Output: Clone {}
 
-struct RevdepVec(Vec<WeakRef<UpdateableNode>>);
+struct RevdepVec(Vec<(NodeId, WeakRef<UpdateableNode>)>);
 
 impl RevdepVec {
     fn new() -> RevdepVec {
@@ -88,37 +166,70 @@ impl RevdepVec {
     }
 
     fn add_revdep(&mut self, revdep: Ref<UpdateableNode>) {
-        self.0.push(Ref::downgrade(&revdep));
+        self.0.push((revdep.id(), Ref::downgrade(&revdep)));
     }
 
     fn remove_revdep(&mut self, revdep: Ref<UpdateableNode>) {
-        use std::ops::Deref;
-
-        let needle = revdep.deref() as *const UpdateableNode;
+        let needle = revdep.id();
 
-        self.0.retain(|weak| {
-            let strong = match weak.upgrade() {
-                None => return false,
-                Some(r) => r,
-            };
-
-            if strong.deref() as *const UpdateableNode == needle {
-                false
-            } else {
-                true
-            }
-        });
+        self.0.retain(|&(id, _)| id != needle);
     }
 
-    fn update_all(&mut self) {
-        self.0.retain(|weak| {
+    /// Upgrades and returns the live reverse dependencies, purging expired ones.
+    fn live_revdeps(&mut self) -> Vec<Ref<UpdateableNode>> {
+        let mut live = Vec::new();
+
+        self.0.retain(|(_, weak)| {
             if let Some(revdep) = weak.upgrade() {
-                revdep.update();
+                live.push(revdep);
                 true
             } else {
                 false
             }
         });
+
+        live
+    }
+}
+
+/// Orders by `height()`, reversed so a `BinaryHeap` of these pops the lowest
+/// height first.
+struct HeightOrdered(Ref<UpdateableNode>);
+
+impl PartialEq for HeightOrdered {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.height() == other.0.height()
+    }
+}
+
+impl Eq for HeightOrdered {}
+
+impl PartialOrd for HeightOrdered {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeightOrdered {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.height().cmp(&self.0.height())
+    }
+}
+
+/// Propagates a change to `seeds` and their revdeps in ascending height
+/// order, updating each node at most once.
+fn propagate(seeds: Vec<Ref<UpdateableNode>>) {
+    let mut visited = HashSet::new();
+    let mut heap: BinaryHeap<HeightOrdered> = seeds.into_iter().map(HeightOrdered).collect();
+
+    while let Some(HeightOrdered(node)) = heap.pop() {
+        if !visited.insert(node.id()) {
+            continue;
+        }
+
+        node.update();
+
+        heap.extend(node.revdeps().into_iter().map(HeightOrdered));
     }
 }
 
@@ -126,6 +237,8 @@ pub struct CachedNode<T: CachableNode> {
     inner_node: Ref<T>,
     cached_value: RefCell<T::Output>,
     revdeps: RefCell<RevdepVec>,
+    height: usize,
+    id: NodeId,
 }
 
 impl<T: CachableNode> Node for CachedNode<T>
@@ -141,36 +254,64 @@ impl<T: CachableNode> Node for CachedNode<T>
 impl<T: CachableNode> UpdateableNode for CachedNode<T> {
     fn update(&self) {
         *self.cached_value.borrow_mut() = self.inner_node.eval();
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
 
-        self.revdeps.borrow_mut().update_all();
+    fn revdeps(&self) -> Vec<Ref<UpdateableNode>> {
+        self.revdeps.borrow_mut().live_revdeps()
+    }
+
+    fn id(&self) -> NodeId {
+        self.id
     }
 }
 
-impl<T: CachableNode> UpdatingNode for CachedNode<T>
+impl<T: CachableNode + 'static> UpdatingNode for CachedNode<T>
     where T::Output: Clone
 {
-    fn add_revdep(&self, revdep: Ref<UpdateableNode>) {
+    fn add_revdep(&self, revdep: Ref<UpdateableNode>) -> Result<(), GraphError> {
+        if is_reachable(&revdep, self.id) {
+            return Err(GraphError::Cycle);
+        }
+
         self.revdeps.borrow_mut().add_revdep(revdep);
+        Ok(())
     }
 
     fn remove_revdep(&self, revdep: Ref<UpdateableNode>) {
         self.revdeps.borrow_mut().remove_revdep(revdep);
     }
+
+    fn height(&self) -> usize {
+        self.height
+    }
 }
 
 impl<T: CachableNode + 'static> CachedNode<T> {
+    /// Panics if the dependency graph would contain a cycle. See `try_new`.
     pub fn new(inner: Ref<T>) -> Ref<CachedNode<T>> {
+        Self::try_new(inner).expect("CachedNode::new: dependency graph would contain a cycle")
+    }
+
+    /// Rejects the wiring if it would close a cycle in the revdep graph.
+    pub fn try_new(inner: Ref<T>) -> Result<Ref<CachedNode<T>>, GraphError> {
         let value = inner.eval();
+        let height = inner.height() + 1;
 
         let node = Ref::new(CachedNode {
             inner_node: inner,
             cached_value: RefCell::new(value),
             revdeps: RefCell::new(RevdepVec::new()),
+            height: height,
+            id: next_node_id(),
         });
 
-        node.inner_node.forward_add_revdep(node.clone());
+        node.inner_node.forward_add_revdep(node.clone())?;
 
-        node
+        Ok(node)
     }
 }
 
@@ -178,6 +319,8 @@ pub struct LazyCachedNode<T: CachableNode> {
     inner_node: Ref<T>,
     cached_value: RefCell<Option<T::Output>>,
     revdeps: RefCell<RevdepVec>,
+    height: usize,
+    id: NodeId,
 }
 
 impl<T: CachableNode> Node for LazyCachedNode<T>
@@ -205,34 +348,63 @@ impl<T: CachableNode> Node for LazyCachedNode<T>
 impl<T: CachableNode> UpdateableNode for LazyCachedNode<T> {
     fn update(&self) {
         *self.cached_value.borrow_mut() = None;
+    }
+
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn revdeps(&self) -> Vec<Ref<UpdateableNode>> {
+        self.revdeps.borrow_mut().live_revdeps()
+    }
 
-        self.revdeps.borrow_mut().update_all();
+    fn id(&self) -> NodeId {
+        self.id
     }
 }
 
-impl<T: CachableNode> UpdatingNode for LazyCachedNode<T>
+impl<T: CachableNode + 'static> UpdatingNode for LazyCachedNode<T>
     where T::Output: Clone
 {
-    fn add_revdep(&self, revdep: Ref<UpdateableNode>) {
+    fn add_revdep(&self, revdep: Ref<UpdateableNode>) -> Result<(), GraphError> {
+        if is_reachable(&revdep, self.id) {
+            return Err(GraphError::Cycle);
+        }
+
         self.revdeps.borrow_mut().add_revdep(revdep);
+        Ok(())
     }
 
     fn remove_revdep(&self, revdep: Ref<UpdateableNode>) {
         self.revdeps.borrow_mut().remove_revdep(revdep);
     }
+
+    fn height(&self) -> usize {
+        self.height
+    }
 }
 
 impl<T: CachableNode + 'static> LazyCachedNode<T> {
+    /// Panics if the dependency graph would contain a cycle. See `try_new`.
     pub fn new(inner: Ref<T>) -> Ref<LazyCachedNode<T>> {
+        Self::try_new(inner).expect("LazyCachedNode::new: dependency graph would contain a cycle")
+    }
+
+    /// Rejects the wiring if it would close a cycle in the revdep graph.
+    pub fn try_new(inner: Ref<T>) -> Result<Ref<LazyCachedNode<T>>, GraphError> {
+        let height = inner.height() + 1;
+
         let node = Ref::new(LazyCachedNode {
             inner_node: inner,
             cached_value: RefCell::new(None),
             revdeps: RefCell::new(RevdepVec::new()),
+            height: height,
+            id: next_node_id(),
         });
 
-        node.inner_node.forward_add_revdep(node.clone());
+        node.inner_node.forward_add_revdep(node.clone())?;
 
-        node
+        Ok(node)
     }
 }
 
@@ -258,21 +430,55 @@ impl<T: Clone> InputNode<T> {
     }
 
     pub fn set(&self, value: T) {
+        let seeds = self.set_deferred(value);
+        propagate(seeds);
+    }
+
+    /// Like `set`, but returns the revdeps to propagate instead of
+    /// propagating them itself, so a caller (namely `Evaluator`) can batch
+    /// several of these together.
+    pub fn set_deferred(&self, value: T) -> Vec<Ref<UpdateableNode>> {
         *self.value.borrow_mut() = value;
 
-        self.revdeps.borrow_mut().update_all();
+        self.revdeps.borrow_mut().live_revdeps()
     }
 }
 
 impl<T: Clone> UpdatingNode for InputNode<T> {
-    fn add_revdep(&self, revdep: Ref<UpdateableNode>) {
+    /// Inputs are always leaves, so no cycle check is needed.
+    fn add_revdep(&self, revdep: Ref<UpdateableNode>) -> Result<(), GraphError> {
         self.revdeps.borrow_mut().add_revdep(revdep);
+        Ok(())
     }
     fn remove_revdep(&self, revdep: Ref<UpdateableNode>) {
         self.revdeps.borrow_mut().remove_revdep(revdep);
     }
 }
 
+/// Batches several `InputNode::set` calls into a single propagation pass.
+pub struct Evaluator {
+    dirty: RefCell<Vec<Ref<UpdateableNode>>>,
+}
+
+impl Evaluator {
+    /// Runs `f` with a fresh `Evaluator`, then propagates its changes in a
+    /// single pass.
+    pub fn transaction<F: FnOnce(&Evaluator)>(f: F) {
+        let evaluator = Evaluator { dirty: RefCell::new(Vec::new()) };
+
+        f(&evaluator);
+
+        propagate(evaluator.dirty.into_inner());
+    }
+
+    /// Sets `input` to `value`; propagation happens when `transaction` returns.
+    pub fn set<T: Clone>(&self, input: &InputNode<T>, value: T) {
+        let revdeps = input.set_deferred(value);
+
+        self.dirty.borrow_mut().extend(revdeps);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -313,4 +519,122 @@ mod tests {
         assert_eq!(input.eval(), 3.0f32);
         assert_eq!(cache.eval(), 3.0f32);
     }
+
+    /// Adds its two children and counts how often `eval()` runs.
+    struct CountingAddNode<A: Node, B: Node>
+        where A::Output: ::std::ops::Add<B::Output>
+    {
+        a: Ref<A>,
+        b: Ref<B>,
+        count: ::std::rc::Rc<::std::cell::Cell<usize>>,
+    }
+
+    impl<A: Node, B: Node> Node for CountingAddNode<A, B>
+        where A::Output: ::std::ops::Add<B::Output>
+    {
+        type Output = <A::Output as ::std::ops::Add<B::Output>>::Output;
+
+        fn eval(&self) -> Self::Output {
+            self.count.set(self.count.get() + 1);
+
+            self.a.eval() + self.b.eval()
+        }
+    }
+
+    impl<A: Node + RevdepForwarder, B: Node + RevdepForwarder> RevdepForwarder for CountingAddNode<A, B>
+        where A::Output: ::std::ops::Add<B::Output>
+    {
+        fn forward_add_revdep(&self, revdep: Ref<UpdateableNode>) -> Result<(), GraphError> {
+            self.a.forward_add_revdep(revdep.clone())?;
+            self.b.forward_add_revdep(revdep)
+        }
+
+        fn forward_remove_revdep(&self, revdep: Ref<UpdateableNode>) {
+            self.a.forward_remove_revdep(revdep.clone());
+            self.b.forward_remove_revdep(revdep);
+        }
+
+        fn height(&self) -> usize {
+            self.a.height().max(self.b.height())
+        }
+    }
+
+    #[test]
+    fn diamond_updates_once_and_glitch_free() {
+        let input = InputNode::new(1.0f32);
+        let a = CachedNode::new(AddNode::new(input.clone(), Ref::new(1.0f32)));
+        let b = CachedNode::new(MulNode::new(input.clone(), Ref::new(2.0f32)));
+
+        let count = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let counting = Ref::new(CountingAddNode {
+            a: a.clone(),
+            b: b.clone(),
+            count: count.clone(),
+        });
+        let c = CachedNode::new(counting);
+
+        assert_eq!(c.eval(), 4.0f32);
+        assert_eq!(count.get(), 1);
+
+        input.set(3.0f32);
+
+        assert_eq!(a.eval(), 4.0f32);
+        assert_eq!(b.eval(), 6.0f32);
+        assert_eq!(c.eval(), 10.0f32);
+        assert_eq!(count.get(), 2);
+    }
+
+    #[test]
+    fn transaction_propagates_once_for_multiple_inputs() {
+        let x = InputNode::new(1.0f32);
+        let y = InputNode::new(1.0f32);
+        let a = CachedNode::new(AddNode::new(x.clone(), Ref::new(1.0f32)));
+        let b = CachedNode::new(AddNode::new(y.clone(), Ref::new(1.0f32)));
+
+        let count = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let counting = Ref::new(CountingAddNode {
+            a: a.clone(),
+            b: b.clone(),
+            count: count.clone(),
+        });
+        let c = CachedNode::new(counting);
+
+        assert_eq!(c.eval(), 4.0f32);
+        assert_eq!(count.get(), 1);
+
+        Evaluator::transaction(|ev| {
+            ev.set(&x, 10.0f32);
+            ev.set(&y, 20.0f32);
+        });
+
+        assert_eq!(c.eval(), 32.0f32);
+        assert_eq!(count.get(), 2);
+    }
+
+    #[test]
+    fn closing_a_cycle_is_rejected() {
+        let input = InputNode::new(1.0f32);
+        let a = CachedNode::new(input.clone());
+        let b = CachedNode::new(a.clone());
+
+        // `a` already notifies `b` (installed when `b` was constructed), so
+        // wiring `b` to notify `a` in turn would close the cycle a -> b -> a.
+        match UpdatingNode::add_revdep(&*b, a.clone() as Ref<UpdateableNode>) {
+            Err(GraphError::Cycle) => {}
+            other => panic!("expected GraphError::Cycle, got {:?}", other.is_ok()),
+        }
+    }
+
+    #[test]
+    fn removing_a_revdep_detaches_only_that_node() {
+        let input = InputNode::new(1.0f32);
+        let a = CachedNode::new(input.clone());
+        let b = CachedNode::new(input.clone());
+
+        UpdatingNode::remove_revdep(&*input, a.clone() as Ref<UpdateableNode>);
+        input.set(5.0f32);
+
+        assert_eq!(a.eval(), 1.0f32);
+        assert_eq!(b.eval(), 5.0f32);
+    }
 }
@@ -1,4 +1,4 @@
-use super::{Node, Ref, RevdepForwarder, UpdateableNode};
+use super::{CachableNode, GraphError, Node, Ref, RevdepForwarder, UpdateableNode};
 
 use std::ops::*;
 
@@ -37,15 +37,19 @@ macro_rules! create_node_for_binary_op {
                   LhsNode: RevdepForwarder,
                   RhsNode: RevdepForwarder
         {
-            fn forward_add_revdep(&self, revdep: Ref<UpdateableNode>) {
-                self.lhs.forward_add_revdep(revdep.clone());
-                self.rhs.forward_add_revdep(revdep);
+            fn forward_add_revdep(&self, revdep: Ref<UpdateableNode>) -> Result<(), GraphError> {
+                self.lhs.forward_add_revdep(revdep.clone())?;
+                self.rhs.forward_add_revdep(revdep)
             }
 
             fn forward_remove_revdep(&self, revdep: Ref<UpdateableNode>) {
                 self.lhs.forward_remove_revdep(revdep.clone());
                 self.rhs.forward_remove_revdep(revdep);
             }
+
+            fn height(&self) -> usize {
+                self.lhs.height().max(self.rhs.height())
+            }
         }
     );
 
@@ -68,6 +72,151 @@ create_node_for_binary_op!(
     BitXorNode, BitXor, bitxor,
 );
 
+/// Applies an arbitrary closure to a single child node's output.
+pub struct MapNode<InNode: Node, Out, F: Fn(InNode::Output) -> Out> {
+    inner: Ref<InNode>,
+    f: F,
+}
+
+impl<InNode: Node, Out, F: Fn(InNode::Output) -> Out> Node for MapNode<InNode, Out, F> {
+    type Output = Out;
+
+    fn eval(&self) -> Self::Output {
+        (self.f)(self.inner.eval())
+    }
+}
+
+impl<InNode: Node, Out, F: Fn(InNode::Output) -> Out> MapNode<InNode, Out, F> {
+    pub fn new(inner: Ref<InNode>, f: F) -> Ref<MapNode<InNode, Out, F>> {
+        Ref::new(MapNode {
+            inner: inner,
+            f: f,
+        })
+    }
+}
+
+impl<InNode: Node + RevdepForwarder, Out, F: Fn(InNode::Output) -> Out> RevdepForwarder
+    for MapNode<InNode, Out, F>
+{
+    fn forward_add_revdep(&self, revdep: Ref<UpdateableNode>) -> Result<(), GraphError> {
+        self.inner.forward_add_revdep(revdep)
+    }
+
+    fn forward_remove_revdep(&self, revdep: Ref<UpdateableNode>) {
+        self.inner.forward_remove_revdep(revdep);
+    }
+
+    fn height(&self) -> usize {
+        self.inner.height()
+    }
+}
+
+/// Combines a dynamically-sized list of same-typed children via a closure.
+pub struct ZipNode<T: Clone, Out, F: Fn(&[T]) -> Out> {
+    children: Vec<Ref<CachableNode<Output = T>>>,
+    f: F,
+}
+
+impl<T: Clone, Out, F: Fn(&[T]) -> Out> Node for ZipNode<T, Out, F> {
+    type Output = Out;
+
+    fn eval(&self) -> Self::Output {
+        let values: Vec<T> = self.children.iter().map(|child| child.eval()).collect();
+
+        (self.f)(&values)
+    }
+}
+
+impl<T: Clone, Out, F: Fn(&[T]) -> Out> ZipNode<T, Out, F> {
+    pub fn new(children: Vec<Ref<CachableNode<Output = T>>>, f: F) -> Ref<ZipNode<T, Out, F>> {
+        Ref::new(ZipNode {
+            children: children,
+            f: f,
+        })
+    }
+}
+
+impl<T: Clone, Out, F: Fn(&[T]) -> Out> RevdepForwarder for ZipNode<T, Out, F> {
+    fn forward_add_revdep(&self, revdep: Ref<UpdateableNode>) -> Result<(), GraphError> {
+        for child in &self.children {
+            child.forward_add_revdep(revdep.clone())?;
+        }
+
+        Ok(())
+    }
+
+    fn forward_remove_revdep(&self, revdep: Ref<UpdateableNode>) {
+        for child in &self.children {
+            child.forward_remove_revdep(revdep.clone());
+        }
+    }
+
+    fn height(&self) -> usize {
+        self.children.iter().map(|child| child.height()).max().unwrap_or(0)
+    }
+}
+
+/// Picks between two branches based on a boolean predicate node.
+pub struct SelectNode<PredNode: Node<Output = bool>, TrueNode: Node, FalseNode: Node<Output = TrueNode::Output>> {
+    pred: Ref<PredNode>,
+    when_true: Ref<TrueNode>,
+    when_false: Ref<FalseNode>,
+}
+
+impl<PredNode: Node<Output = bool>, TrueNode: Node, FalseNode: Node<Output = TrueNode::Output>> Node
+    for SelectNode<PredNode, TrueNode, FalseNode>
+{
+    type Output = TrueNode::Output;
+
+    fn eval(&self) -> Self::Output {
+        if self.pred.eval() {
+            self.when_true.eval()
+        } else {
+            self.when_false.eval()
+        }
+    }
+}
+
+impl<PredNode: Node<Output = bool>, TrueNode: Node, FalseNode: Node<Output = TrueNode::Output>>
+    SelectNode<PredNode, TrueNode, FalseNode>
+{
+    pub fn new(pred: Ref<PredNode>,
+               when_true: Ref<TrueNode>,
+               when_false: Ref<FalseNode>)
+               -> Ref<SelectNode<PredNode, TrueNode, FalseNode>> {
+        Ref::new(SelectNode {
+            pred: pred,
+            when_true: when_true,
+            when_false: when_false,
+        })
+    }
+}
+
+impl<PredNode, TrueNode, FalseNode> RevdepForwarder for SelectNode<PredNode, TrueNode, FalseNode>
+    where PredNode: Node<Output = bool> + RevdepForwarder,
+          TrueNode: Node + RevdepForwarder,
+          FalseNode: Node<Output = TrueNode::Output> + RevdepForwarder
+{
+    fn forward_add_revdep(&self, revdep: Ref<UpdateableNode>) -> Result<(), GraphError> {
+        self.pred.forward_add_revdep(revdep.clone())?;
+        self.when_true.forward_add_revdep(revdep.clone())?;
+        self.when_false.forward_add_revdep(revdep)
+    }
+
+    fn forward_remove_revdep(&self, revdep: Ref<UpdateableNode>) {
+        self.pred.forward_remove_revdep(revdep.clone());
+        self.when_true.forward_remove_revdep(revdep.clone());
+        self.when_false.forward_remove_revdep(revdep);
+    }
+
+    fn height(&self) -> usize {
+        self.pred
+            .height()
+            .max(self.when_true.height())
+            .max(self.when_false.height())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -120,4 +269,53 @@ mod tests {
 
         assert_eq!(add.eval(), 4u8);
     }
+
+    #[test]
+    fn map_node_applies_closure() {
+        let input = InputNode::new(4.0f32);
+        let sqrt = CachedNode::new(MapNode::new(input.clone(), |x: f32| x.sqrt()));
+
+        assert_eq!(sqrt.eval(), 2.0f32);
+
+        input.set(9.0f32);
+
+        assert_eq!(sqrt.eval(), 3.0f32);
+    }
+
+    #[test]
+    fn zip_node_sums_a_dynamic_list_of_inputs() {
+        let a = InputNode::new(1.0f32);
+        let b = InputNode::new(2.0f32);
+        let c = InputNode::new(3.0f32);
+
+        let children = vec![a.clone() as Ref<CachableNode<Output = f32>>,
+                             b.clone() as Ref<CachableNode<Output = f32>>,
+                             c.clone() as Ref<CachableNode<Output = f32>>];
+        let sum = CachedNode::new(ZipNode::new(children, |values: &[f32]| -> f32 { values.iter().sum() }));
+
+        assert_eq!(sum.eval(), 6.0f32);
+
+        a.set(10.0f32);
+
+        assert_eq!(sum.eval(), 15.0f32);
+    }
+
+    #[test]
+    fn select_node_follows_the_predicate() {
+        let pred = InputNode::new(true);
+        let when_true = InputNode::new(1.0f32);
+        let when_false = InputNode::new(2.0f32);
+
+        let select = CachedNode::new(SelectNode::new(pred.clone(), when_true.clone(), when_false.clone()));
+
+        assert_eq!(select.eval(), 1.0f32);
+
+        pred.set(false);
+
+        assert_eq!(select.eval(), 2.0f32);
+
+        when_false.set(42.0f32);
+
+        assert_eq!(select.eval(), 42.0f32);
+    }
 }